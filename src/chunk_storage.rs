@@ -0,0 +1,365 @@
+use super::address::Address;
+use super::repository::GCStats;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A place chunks can be stored and retrieved from, addressed by their
+/// content address. Implementations are free to choose how they lay
+/// chunks out on disk/over the network, as long as `add_chunk` followed
+/// by `get_chunk` for the same address round-trips the bytes.
+pub trait Engine {
+    fn add_chunk(&mut self, addr: &Address, buf: Vec<u8>) -> Result<(), failure::Error>;
+    fn get_chunk(&mut self, addr: &Address) -> Result<Vec<u8>, failure::Error>;
+    fn sync(&mut self) -> Result<(), failure::Error>;
+
+    /// Delete chunks for which `reachable` returns false, except any
+    /// created at or after `not_before` (see `Repo::gc`'s doc comment
+    /// for why that grace period is needed).
+    fn gc(
+        &mut self,
+        reachable: &dyn Fn(&Address) -> bool,
+        not_before: SystemTime,
+    ) -> Result<GCStats, failure::Error>;
+
+    /// Report which of `addrs` already have a stored chunk, in the same
+    /// order. The default asks one at a time via `get_chunk`; engines
+    /// that can answer more cheaply (e.g. a single round trip for a
+    /// remote engine) should override this.
+    fn has_chunks(&mut self, addrs: &[Address]) -> Result<Vec<bool>, failure::Error> {
+        addrs.iter().map(|addr| Ok(self.get_chunk(addr).is_ok())).collect()
+    }
+}
+
+pub struct LocalStorage {
+    dir: PathBuf,
+    _n_workers: usize,
+}
+
+impl LocalStorage {
+    pub fn new(dir: &Path, n_workers: usize) -> LocalStorage {
+        LocalStorage {
+            dir: dir.to_path_buf(),
+            _n_workers: n_workers,
+        }
+    }
+
+    fn chunk_path(&self, addr: &Address) -> PathBuf {
+        let mut p = self.dir.clone();
+        p.push(addr.to_string());
+        p
+    }
+}
+
+impl Engine for LocalStorage {
+    fn add_chunk(&mut self, addr: &Address, buf: Vec<u8>) -> Result<(), failure::Error> {
+        let path = self.chunk_path(addr);
+        if path.exists() {
+            return Ok(());
+        }
+        std::fs::write(path, buf)?;
+        Ok(())
+    }
+
+    fn get_chunk(&mut self, addr: &Address) -> Result<Vec<u8>, failure::Error> {
+        Ok(std::fs::read(self.chunk_path(addr))?)
+    }
+
+    fn sync(&mut self) -> Result<(), failure::Error> {
+        Ok(())
+    }
+
+    fn has_chunks(&mut self, addrs: &[Address]) -> Result<Vec<bool>, failure::Error> {
+        Ok(addrs
+            .iter()
+            .map(|addr| self.chunk_path(addr).exists())
+            .collect())
+    }
+
+    fn gc(
+        &mut self,
+        reachable: &dyn Fn(&Address) -> bool,
+        not_before: SystemTime,
+    ) -> Result<GCStats, failure::Error> {
+        let mut stats = GCStats {
+            chunks_deleted: 0,
+            bytes_freed: 0,
+            bytes_remaining: 0,
+        };
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let addr = match Address::from_hex_str(&entry.file_name().to_string_lossy()) {
+                Ok(addr) => addr,
+                Err(_) => continue,
+            };
+            let metadata = entry.metadata()?;
+            let len = metadata.len() as usize;
+            let too_new = matches!(metadata.modified(), Ok(mtime) if mtime >= not_before);
+            if reachable(&addr) || too_new {
+                stats.bytes_remaining += len;
+            } else {
+                // Another concurrent gc() may have already deleted this
+                // chunk; that's not an error, just nothing left to do.
+                match std::fs::remove_file(entry.path()) {
+                    Ok(()) => {
+                        stats.chunks_deleted += 1;
+                        stats.bytes_freed += len;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Stores chunks as objects in an S3-compatible bucket (AWS S3, Garage,
+/// MinIO, ...), keyed by the hex-encoded chunk address under `prefix`.
+/// This gives the same content-addressed dedup as `LocalStorage` without
+/// needing a POSIX filesystem on the remote side.
+///
+/// Each chunk is a single `put_object`, not a multipart upload -- chunks
+/// are small enough (well under S3's 5GB single-PUT limit) that the
+/// extra bookkeeping multipart would need (upload IDs, part numbers,
+/// a completion call per chunk) buys nothing here. `sync`/`add_chunk`'s
+/// "in flight" bookkeeping below is about bounding how many of those
+/// single PUTs run concurrently, not about assembling parts of one.
+pub struct S3Storage {
+    client: rusoto_s3::S3Client,
+    bucket: String,
+    prefix: String,
+    runtime: tokio::runtime::Runtime,
+    in_flight: Vec<(Address, tokio::task::JoinHandle<Result<(), failure::Error>>)>,
+}
+
+/// Caps how many `put_object` calls run concurrently. Without this, a
+/// large backup would spawn one task per chunk with no backpressure at
+/// all -- `add_chunk` never blocks, so nothing would stop `in_flight`
+/// from growing to the size of the entire backup.
+const MAX_IN_FLIGHT_PUTS: usize = 64;
+
+impl S3Storage {
+    pub fn new(bucket: &str, prefix: &str, endpoint: &str, region: &str) -> S3Storage {
+        let region = rusoto_core::Region::Custom {
+            name: region.to_string(),
+            endpoint: endpoint.to_string(),
+        };
+        let client = rusoto_s3::S3Client::new(region);
+        let runtime = tokio::runtime::Runtime::new().expect("failed to start s3 runtime");
+        S3Storage {
+            client,
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            runtime,
+            in_flight: Vec::new(),
+        }
+    }
+
+    fn object_key(&self, addr: &Address) -> String {
+        format!("{}/{}", self.prefix.trim_end_matches('/'), addr.to_string())
+    }
+
+    /// Block until every currently in-flight PUT has completed,
+    /// propagating the first error encountered. Called by `sync` to
+    /// flush before returning, and by `add_chunk` once
+    /// `MAX_IN_FLIGHT_PUTS` is reached so a large backup applies
+    /// backpressure instead of spawning unbounded concurrent PUTs.
+    fn wait_for_in_flight(&mut self) -> Result<(), failure::Error> {
+        let in_flight = std::mem::take(&mut self.in_flight);
+        self.runtime.block_on(async move {
+            for (_, handle) in in_flight {
+                handle.await??;
+            }
+            Ok(())
+        })
+    }
+
+    /// Block until any PUT still in flight for `addr` specifically has
+    /// completed. `get_chunk` calls this first so the `Engine` trait's
+    /// round-trip guarantee (`add_chunk` then `get_chunk` for the same
+    /// address sees the bytes) holds even with no `sync()` in between.
+    fn wait_for_addr(&mut self, addr: &Address) -> Result<(), failure::Error> {
+        let (pending, remaining): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.in_flight).into_iter().partition(|(a, _)| a == addr);
+        self.in_flight = remaining;
+        self.runtime.block_on(async move {
+            for (_, handle) in pending {
+                handle.await??;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Engine for S3Storage {
+    fn add_chunk(&mut self, addr: &Address, buf: Vec<u8>) -> Result<(), failure::Error> {
+        use rusoto_s3::S3;
+
+        if self.in_flight.len() >= MAX_IN_FLIGHT_PUTS {
+            self.wait_for_in_flight()?;
+        }
+
+        let client = self.client.clone();
+        let bucket = self.bucket.clone();
+        let key = self.object_key(addr);
+        let handle = self.runtime.spawn(async move {
+            client
+                .put_object(rusoto_s3::PutObjectRequest {
+                    bucket,
+                    key,
+                    body: Some(buf.into()),
+                    ..Default::default()
+                })
+                .await?;
+            Ok(())
+        });
+        self.in_flight.push((*addr, handle));
+        Ok(())
+    }
+
+    fn get_chunk(&mut self, addr: &Address) -> Result<Vec<u8>, failure::Error> {
+        use futures::stream::TryStreamExt;
+        use rusoto_s3::S3;
+
+        self.wait_for_addr(addr)?;
+
+        let client = self.client.clone();
+        let bucket = self.bucket.clone();
+        let key = self.object_key(addr);
+        self.runtime.block_on(async move {
+            let resp = client
+                .get_object(rusoto_s3::GetObjectRequest { bucket, key, ..Default::default() })
+                .await?;
+            let body = resp
+                .body
+                .ok_or_else(|| failure::format_err!("s3 object has no body"))?;
+            let bytes = body
+                .map_ok(|b| b.to_vec())
+                .try_concat()
+                .await?;
+            Ok(bytes)
+        })
+    }
+
+    fn has_chunks(&mut self, addrs: &[Address]) -> Result<Vec<bool>, failure::Error> {
+        use rusoto_core::RusotoError;
+        use rusoto_s3::S3;
+
+        let client = self.client.clone();
+        let bucket = self.bucket.clone();
+        let keys: Vec<String> = addrs.iter().map(|addr| self.object_key(addr)).collect();
+        self.runtime.block_on(async move {
+            let checks = keys.into_iter().map(|key| {
+                let client = client.clone();
+                let bucket = bucket.clone();
+                async move {
+                    match client
+                        .head_object(rusoto_s3::HeadObjectRequest {
+                            bucket,
+                            key,
+                            ..Default::default()
+                        })
+                        .await
+                    {
+                        Ok(_) => Ok(true),
+                        // S3 answers a missing object with a bare 404 and
+                        // no body, which rusoto surfaces as `Unknown`
+                        // rather than a typed service error. Anything
+                        // else (a throttle, a 5xx) is a transient failure,
+                        // not evidence the chunk is absent -- propagate
+                        // it instead of reporting a false miss.
+                        Err(RusotoError::Unknown(resp)) if resp.status.as_u16() == 404 => Ok(false),
+                        Err(e) => Err(failure::Error::from(e)),
+                    }
+                }
+            });
+            futures::future::join_all(checks)
+                .await
+                .into_iter()
+                .collect::<Result<Vec<bool>, failure::Error>>()
+        })
+    }
+
+    fn sync(&mut self) -> Result<(), failure::Error> {
+        self.wait_for_in_flight()
+    }
+
+    fn gc(
+        &mut self,
+        reachable: &dyn Fn(&Address) -> bool,
+        not_before: SystemTime,
+    ) -> Result<GCStats, failure::Error> {
+        use rusoto_s3::S3;
+
+        let mut stats = GCStats {
+            chunks_deleted: 0,
+            bytes_freed: 0,
+            bytes_remaining: 0,
+        };
+
+        let client = self.client.clone();
+        let bucket = self.bucket.clone();
+        let prefix = format!("{}/", self.prefix.trim_end_matches('/'));
+        let not_before: chrono::DateTime<chrono::Utc> = not_before.into();
+
+        self.runtime.block_on(async move {
+            let mut continuation_token = None;
+            loop {
+                let resp = client
+                    .list_objects_v2(rusoto_s3::ListObjectsV2Request {
+                        bucket: bucket.clone(),
+                        prefix: Some(prefix.clone()),
+                        continuation_token: continuation_token.clone(),
+                        ..Default::default()
+                    })
+                    .await?;
+
+                for obj in resp.contents.unwrap_or_default() {
+                    let key = match obj.key {
+                        Some(k) => k,
+                        None => continue,
+                    };
+                    let hex_addr = match key.strip_prefix(&prefix) {
+                        Some(h) => h,
+                        None => continue,
+                    };
+                    let addr = match Address::from_hex_str(hex_addr) {
+                        Ok(addr) => addr,
+                        Err(_) => continue,
+                    };
+                    let len = obj.size.unwrap_or(0) as usize;
+                    // An unparseable or missing last-modified time is
+                    // treated as "too new to touch": better to leave a
+                    // chunk around an extra GC cycle than to risk
+                    // deleting one a concurrent upload just wrote.
+                    let too_new = match &obj.last_modified {
+                        Some(s) => match chrono::DateTime::parse_from_rfc3339(s) {
+                            Ok(t) => t.with_timezone(&chrono::Utc) >= not_before,
+                            Err(_) => true,
+                        },
+                        None => true,
+                    };
+                    if reachable(&addr) || too_new {
+                        stats.bytes_remaining += len;
+                    } else {
+                        client
+                            .delete_object(rusoto_s3::DeleteObjectRequest {
+                                bucket: bucket.clone(),
+                                key,
+                                ..Default::default()
+                            })
+                            .await?;
+                        stats.chunks_deleted += 1;
+                        stats.bytes_freed += len;
+                    }
+                }
+
+                continuation_token = resp.next_continuation_token;
+                if continuation_token.is_none() {
+                    break;
+                }
+            }
+            Ok(stats)
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use super::address::Address;
+use super::chunk_storage;
+use std::collections::{HashMap, VecDeque};
+
+/// Interior nodes are unencrypted chunks (their content is just
+/// addresses, which don't leak file data) holding a flat sequence of
+/// fixed-size records: the child's `Address` followed by the total
+/// byte length of the subtree rooted at it (a leaf's own length, or the
+/// sum of its children's lengths for another interior node).
+pub const RECORD_SIZE: usize = Address::BYTES + 8;
+
+pub fn parse_node(data: &[u8]) -> impl Iterator<Item = (Address, u64)> + '_ {
+    data.chunks_exact(RECORD_SIZE).map(|rec| {
+        let (addr_bytes, len_bytes) = rec.split_at(Address::BYTES);
+        let len = u64::from_be_bytes(len_bytes.try_into().unwrap());
+        (Address::from_bytes(addr_bytes), len)
+    })
+}
+
+/// Walks a content-addressed tree lazily, fetching only the interior
+/// nodes needed to discover the addresses the caller actually wants to
+/// descend into.
+pub struct TreeReader<'a> {
+    storage_engine: &'a mut dyn chunk_storage::Engine,
+    queue: VecDeque<(usize, Address)>,
+    lengths: HashMap<Address, u64>,
+}
+
+impl<'a> TreeReader<'a> {
+    pub fn new(
+        storage_engine: &'a mut dyn chunk_storage::Engine,
+        height: usize,
+        root: &Address,
+    ) -> Self {
+        let mut queue = VecDeque::new();
+        queue.push_back((height, *root));
+        TreeReader {
+            storage_engine,
+            queue,
+            lengths: HashMap::new(),
+        }
+    }
+
+    /// Pop the next `(height, addr)` the reader knows about, without
+    /// fetching it.
+    pub fn next_addr(&mut self) -> Result<Option<(usize, Address)>, failure::Error> {
+        Ok(self.queue.pop_front())
+    }
+
+    /// Fetch the interior node at `addr` and enqueue its children, which
+    /// live at `height`. Callers should only do this for addresses they
+    /// have not already visited.
+    pub fn push_addr(&mut self, height: usize, addr: &Address) -> Result<(), failure::Error> {
+        let data = self.storage_engine.get_chunk(addr)?;
+        for (child, len) in parse_node(&data) {
+            self.queue.push_back((height, child));
+            self.lengths.insert(child, len);
+        }
+        Ok(())
+    }
+
+    /// The subtree length recorded for `addr` by its parent, if this
+    /// reader has fetched that parent yet.
+    pub fn length_of(&self, addr: &Address) -> Option<u64> {
+        self.lengths.get(addr).copied()
+    }
+}
@@ -0,0 +1,247 @@
+use super::address::Address;
+use super::crypto;
+use super::htree;
+use super::repository::Repo;
+use fuse::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+const TTL: Duration = Duration::from_secs(1);
+const NODE_CACHE_SIZE: usize = 256;
+
+const ROOT_INO: u64 = 1;
+const FILE_INO: u64 = 2;
+
+/// A read-only view of a single `Item`'s content tree, mounted as a FUSE
+/// filesystem with one entry: the item's content, named after the
+/// repository item id. `readdir`/`lookup` answer from fixed entries;
+/// `read` walks the tree lazily via `htree`, fetching only the chunks
+/// that cover the requested offset instead of extracting the whole item
+/// up front.
+struct ItemFilesystem {
+    repo: Repo,
+    file_name: String,
+    size: u64,
+    tree_height: usize,
+    root_addr: Address,
+    encrypt_header: crypto::VersionedEncryptionHeader,
+    // Decrypted leaf content *and* raw (unencrypted) interior node
+    // bytes, both keyed by address. Caching interior nodes too is what
+    // makes repeated sequential reads avoid re-fetching them; caching
+    // leaves is what makes re-reading the same region avoid
+    // re-decrypting it.
+    node_cache: lru::LruCache<Address, Vec<u8>>,
+}
+
+impl ItemFilesystem {
+    fn node_bytes(&mut self, height: usize, addr: &Address) -> Result<Vec<u8>, failure::Error> {
+        if let Some(data) = self.node_cache.get(addr) {
+            return Ok(data.clone());
+        }
+        let mut storage_engine = self.repo.storage_engine()?;
+        let raw = storage_engine.get_chunk(addr)?;
+        let data = if height == 0 {
+            crypto::decrypt(&self.encrypt_header, &raw)?
+        } else {
+            raw
+        };
+        self.node_cache.put(*addr, data.clone());
+        Ok(data)
+    }
+
+    /// Append the part of the subtree rooted at `addr` (which covers
+    /// `[subtree_start, subtree_start + subtree_len)`) that overlaps
+    /// `[want_start, want_end)` to `out`. Subtrees that don't overlap
+    /// the requested range are skipped without being fetched at all.
+    fn collect_range(
+        &mut self,
+        height: usize,
+        addr: Address,
+        subtree_start: u64,
+        subtree_len: u64,
+        want_start: u64,
+        want_end: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), failure::Error> {
+        let subtree_end = subtree_start + subtree_len;
+        if subtree_end <= want_start || subtree_start >= want_end {
+            return Ok(());
+        }
+
+        let data = self.node_bytes(height, &addr)?;
+
+        if height == 0 {
+            let lo = want_start.saturating_sub(subtree_start) as usize;
+            let hi = std::cmp::min(subtree_len, want_end - subtree_start) as usize;
+            out.extend_from_slice(&data[lo..hi]);
+            return Ok(());
+        }
+
+        let mut child_start = subtree_start;
+        for (child_addr, child_len) in htree::parse_node(&data) {
+            self.collect_range(
+                height - 1,
+                child_addr,
+                child_start,
+                child_len,
+                want_start,
+                want_end,
+                out,
+            )?;
+            child_start += child_len;
+            if child_start >= want_end {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_range(&mut self, offset: u64, size: u32) -> Result<Vec<u8>, failure::Error> {
+        let want_start = std::cmp::min(offset, self.size);
+        let want_end = std::cmp::min(offset + size as u64, self.size);
+        let mut out = Vec::with_capacity((want_end - want_start) as usize);
+        if want_start < want_end {
+            self.collect_range(
+                self.tree_height,
+                self.root_addr,
+                0,
+                self.size,
+                want_start,
+                want_end,
+                &mut out,
+            )?;
+        }
+        Ok(out)
+    }
+
+    fn file_attr(&self) -> FileAttr {
+        FileAttr {
+            ino: FILE_INO,
+            size: self.size,
+            blocks: (self.size + 511) / 512,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn dir_attr(&self) -> FileAttr {
+        FileAttr {
+            ino: ROOT_INO,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for ItemFilesystem {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == ROOT_INO && name == OsStr::new(&self.file_name) {
+            reply.entry(&TTL, &self.file_attr(), 0);
+        } else {
+            reply.error(libc::ENOENT);
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match ino {
+            ROOT_INO => reply.attr(&TTL, &self.dir_attr()),
+            FILE_INO => reply.attr(&TTL, &self.file_attr()),
+            _ => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != ROOT_INO {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        let entries = [
+            (ROOT_INO, FileType::Directory, ".".to_string()),
+            (ROOT_INO, FileType::Directory, "..".to_string()),
+            (FILE_INO, FileType::RegularFile, self.file_name.clone()),
+        ];
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        if ino != FILE_INO {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        match self.read_range(offset as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(_) => reply.error(libc::EIO),
+        }
+    }
+}
+
+impl Repo {
+    /// Mount `item_id` at `mountpoint` read-only, blocking until it is
+    /// unmounted (e.g. via `umount`/`fusermount -u`).
+    pub fn mount_item(&self, item_id: i64, mountpoint: &Path) -> Result<(), failure::Error> {
+        let mut repo = Repo::open(self.path(), super::repository::OpenMode::Shared)?;
+        let item = repo
+            .lookup_item_by_id(item_id)?
+            .ok_or_else(|| failure::format_err!("no such item: {}", item_id))?;
+
+        let fs = ItemFilesystem {
+            repo,
+            file_name: item_id.to_string(),
+            size: item.metadata.size,
+            tree_height: item.metadata.tree_height,
+            root_addr: item.metadata.address,
+            encrypt_header: item.metadata.encrypt_header,
+            node_cache: lru::LruCache::new(NODE_CACHE_SIZE),
+        };
+
+        let options = ["-o", "ro", "-o", "fsname=bupstash"]
+            .iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        fuse::mount(fs, mountpoint, &options)?;
+        Ok(())
+    }
+}
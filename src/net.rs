@@ -0,0 +1,279 @@
+use super::address::Address;
+use super::hydrogen;
+use super::repository::{GCStats, Item, ItemMetadata, OpenMode, Repo};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+const FRAME_CONTEXT: [u8; 8] = *b"bupstash";
+
+/// Frames are bincode-serialized `Request`/`Response` values; chunk
+/// bodies are the largest thing that ever goes through one, so this
+/// only needs enough headroom for a chunk plus its envelope, not an
+/// arbitrary amount.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Request {
+    AddItem(ItemMetadata),
+    LookupItemById(i64),
+    WalkAllItems,
+    Gc,
+    AddChunk(Address, Vec<u8>),
+    GetChunk(Address),
+    HasChunks(Vec<Address>),
+    Sync,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Response {
+    ItemId(i64),
+    Item(Option<Item>),
+    Items(Vec<Item>),
+    GCStats(GCStats),
+    Chunk(Vec<u8>),
+    ChunkPresence(Vec<bool>),
+    Ok,
+    Err(String),
+}
+
+/// A length-prefixed, `secretbox`-sealed channel over a `TcpStream`. Each
+/// direction has its own monotonically increasing 64 bit tag used as the
+/// nonce counter; frames that arrive with a tag other than the expected
+/// next one are rejected, which rules out replay and reordering.
+struct SecureChannel {
+    stream: TcpStream,
+    tx_key: [u8; hydrogen::SECRETBOX_KEYBYTES],
+    rx_key: [u8; hydrogen::SECRETBOX_KEYBYTES],
+    tx_tag: u64,
+    rx_tag: u64,
+}
+
+impl SecureChannel {
+    fn send<T: Serialize>(&mut self, msg: &T) -> Result<(), failure::Error> {
+        let pt = bincode::serialize(msg)?;
+        let mut ct = vec![0u8; pt.len() + hydrogen::SECRETBOX_HEADERBYTES];
+        hydrogen::secretbox_encrypt(&mut ct, &pt, self.tx_tag, FRAME_CONTEXT, &self.tx_key);
+        self.tx_tag += 1;
+        self.stream.write_all(&(ct.len() as u32).to_be_bytes())?;
+        self.stream.write_all(&ct)?;
+        Ok(())
+    }
+
+    fn recv<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, failure::Error> {
+        let mut len_buf = [0u8; 4];
+        self.stream.read_exact(&mut len_buf)?;
+        let ct_len = u32::from_be_bytes(len_buf) as usize;
+        // The length prefix is unauthenticated, so never trust it far
+        // enough to subtract-overflow or to allocate an attacker-chosen
+        // amount of memory before we've verified anything.
+        if ct_len < hydrogen::SECRETBOX_HEADERBYTES || ct_len > MAX_FRAME_SIZE {
+            failure::bail!("frame size {} out of bounds, rejecting connection", ct_len);
+        }
+        let mut ct = vec![0u8; ct_len];
+        self.stream.read_exact(&mut ct)?;
+        let mut pt = vec![0u8; ct_len - hydrogen::SECRETBOX_HEADERBYTES];
+        if !hydrogen::secretbox_decrypt(&mut pt, &ct, self.rx_tag, FRAME_CONTEXT, &self.rx_key) {
+            failure::bail!("bad frame tag or corrupt ciphertext, rejecting connection");
+        }
+        self.rx_tag += 1;
+        Ok(bincode::deserialize(&pt)?)
+    }
+}
+
+/// Client-side handle to a repository served over the network. Every
+/// method proxies the matching `Repo` operation as a request/response
+/// pair over the encrypted channel, so chunk traffic never touches disk
+/// on the client and the sqlite database stays entirely server-side.
+pub struct RemoteRepo {
+    chan: SecureChannel,
+}
+
+impl RemoteRepo {
+    pub fn connect(
+        addr: &str,
+        server_pk: &[u8; hydrogen::KX_PUBLICKEYBYTES],
+        psk: &[u8; hydrogen::KX_PSKBYTES],
+    ) -> Result<RemoteRepo, failure::Error> {
+        let mut stream = TcpStream::connect(addr)?;
+        let (tx_key, rx_key, packet1) = hydrogen::kx_n_1(psk, server_pk);
+        stream.write_all(&packet1)?;
+        Ok(RemoteRepo {
+            chan: SecureChannel {
+                stream,
+                tx_key,
+                rx_key,
+                tx_tag: 0,
+                rx_tag: 0,
+            },
+        })
+    }
+
+    fn roundtrip(&mut self, req: Request) -> Result<Response, failure::Error> {
+        self.chan.send(&req)?;
+        match self.chan.recv::<Response>()? {
+            Response::Err(msg) => failure::bail!("remote repository error: {}", msg),
+            resp => Ok(resp),
+        }
+    }
+
+    pub fn add_item(&mut self, metadata: ItemMetadata) -> Result<i64, failure::Error> {
+        match self.roundtrip(Request::AddItem(metadata))? {
+            Response::ItemId(id) => Ok(id),
+            _ => failure::bail!("unexpected response to add_item"),
+        }
+    }
+
+    pub fn lookup_item_by_id(&mut self, id: i64) -> Result<Option<Item>, failure::Error> {
+        match self.roundtrip(Request::LookupItemById(id))? {
+            Response::Item(item) => Ok(item),
+            _ => failure::bail!("unexpected response to lookup_item_by_id"),
+        }
+    }
+
+    pub fn walk_all_items(&mut self) -> Result<Vec<Item>, failure::Error> {
+        match self.roundtrip(Request::WalkAllItems)? {
+            Response::Items(items) => Ok(items),
+            _ => failure::bail!("unexpected response to walk_all_items"),
+        }
+    }
+
+    pub fn gc(&mut self) -> Result<GCStats, failure::Error> {
+        match self.roundtrip(Request::Gc)? {
+            Response::GCStats(stats) => Ok(stats),
+            _ => failure::bail!("unexpected response to gc"),
+        }
+    }
+}
+
+impl super::chunk_storage::Engine for RemoteRepo {
+    fn add_chunk(&mut self, addr: &Address, buf: Vec<u8>) -> Result<(), failure::Error> {
+        match self.roundtrip(Request::AddChunk(*addr, buf))? {
+            Response::Ok => Ok(()),
+            _ => failure::bail!("unexpected response to add_chunk"),
+        }
+    }
+
+    fn get_chunk(&mut self, addr: &Address) -> Result<Vec<u8>, failure::Error> {
+        match self.roundtrip(Request::GetChunk(*addr))? {
+            Response::Chunk(data) => Ok(data),
+            _ => failure::bail!("unexpected response to get_chunk"),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), failure::Error> {
+        match self.roundtrip(Request::Sync)? {
+            Response::Ok => Ok(()),
+            _ => failure::bail!("unexpected response to sync"),
+        }
+    }
+
+    fn has_chunks(&mut self, addrs: &[Address]) -> Result<Vec<bool>, failure::Error> {
+        match self.roundtrip(Request::HasChunks(addrs.to_vec()))? {
+            Response::ChunkPresence(present) => Ok(present),
+            _ => failure::bail!("unexpected response to has_chunks"),
+        }
+    }
+
+    fn gc(
+        &mut self,
+        _reachable: &dyn Fn(&Address) -> bool,
+        _not_before: std::time::SystemTime,
+    ) -> Result<GCStats, failure::Error> {
+        // The server computes its own reachable set and grace period
+        // against its own clock when it runs `Repo::gc`; the ones this
+        // caller would supply don't apply to a remote repository.
+        RemoteRepo::gc(self)
+    }
+}
+
+/// Accepts connections on `listener` and serves `repo_path` to clients
+/// holding `psk`, until the process is killed. Each connection gets its
+/// own `Repo` handle and session keys derived from the N-handshake.
+pub fn serve(
+    listener: TcpListener,
+    repo_path: &Path,
+    pk: &[u8; hydrogen::KX_PUBLICKEYBYTES],
+    sk: &[u8; hydrogen::KX_SECRETKEYBYTES],
+    psk: &[u8; hydrogen::KX_PSKBYTES],
+) -> Result<(), failure::Error> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let pk = *pk;
+        let sk = *sk;
+        let psk = *psk;
+        let repo_path = repo_path.to_path_buf();
+        // The handshake itself blocks on client input, so it must not
+        // run on the accept loop: a slow or silent client would stall
+        // every other incoming connection behind it.
+        std::thread::spawn(move || {
+            if let Some(mut chan) = accept_handshake(stream, &pk, &sk, &psk) {
+                if let Ok(mut repo) = Repo::open(&repo_path, OpenMode::Shared) {
+                    let _ = serve_connection(&mut chan, &mut repo);
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+fn accept_handshake(
+    mut stream: TcpStream,
+    pk: &[u8; hydrogen::KX_PUBLICKEYBYTES],
+    sk: &[u8; hydrogen::KX_SECRETKEYBYTES],
+    psk: &[u8; hydrogen::KX_PSKBYTES],
+) -> Option<SecureChannel> {
+    let mut packet1 = [0u8; hydrogen::KX_N_PACKET1BYTES];
+    stream.read_exact(&mut packet1).ok()?;
+    let (tx_key, rx_key) = hydrogen::kx_n_2(&packet1, psk, pk, sk)?;
+    Some(SecureChannel {
+        stream,
+        tx_key,
+        rx_key,
+        tx_tag: 0,
+        rx_tag: 0,
+    })
+}
+
+fn serve_connection(chan: &mut SecureChannel, repo: &mut Repo) -> Result<(), failure::Error> {
+    loop {
+        let req: Request = match chan.recv() {
+            Ok(req) => req,
+            Err(_) => return Ok(()),
+        };
+        let resp = match handle_request(req, repo) {
+            Ok(resp) => resp,
+            Err(e) => Response::Err(e.to_string()),
+        };
+        chan.send(&resp)?;
+    }
+}
+
+fn handle_request(req: Request, repo: &mut Repo) -> Result<Response, failure::Error> {
+    Ok(match req {
+        Request::AddItem(metadata) => Response::ItemId(repo.add_item(metadata)?),
+        Request::LookupItemById(id) => Response::Item(repo.lookup_item_by_id(id)?),
+        Request::WalkAllItems => {
+            let mut items = Vec::new();
+            repo.walk_all_items(&mut |mut batch| {
+                items.append(&mut batch);
+                Ok(())
+            })?;
+            Response::Items(items)
+        }
+        Request::Gc => Response::GCStats(repo.gc()?),
+        Request::AddChunk(addr, buf) => {
+            repo.storage_engine()?.add_chunk(&addr, buf)?;
+            Response::Ok
+        }
+        Request::GetChunk(addr) => Response::Chunk(repo.storage_engine()?.get_chunk(&addr)?),
+        Request::HasChunks(addrs) => {
+            Response::ChunkPresence(repo.storage_engine()?.has_chunks(&addrs)?)
+        }
+        Request::Sync => {
+            repo.storage_engine()?.sync()?;
+            Response::Ok
+        }
+    })
+}
@@ -26,9 +26,15 @@ pub enum RepoError {
     UnsupportedSchemaVersion,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum StorageEngineSpec {
     Local,
+    S3 {
+        bucket: String,
+        prefix: String,
+        endpoint: String,
+        region: String,
+    },
 }
 
 pub enum OpenMode {
@@ -55,6 +61,10 @@ pub struct ItemMetadata {
     pub encrypt_header: crypto::VersionedEncryptionHeader,
     pub encrypted_tags: Vec<u8>,
     pub address: Address,
+    /// Total plaintext size of the item, in bytes. Recorded by the
+    /// writer so readers (e.g. the FUSE mount) know the file size
+    /// without having to fetch and decrypt every leaf chunk up front.
+    pub size: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -64,6 +74,14 @@ pub struct GCStats {
     pub bytes_remaining: usize,
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct VerifyStats {
+    pub chunks_checked: usize,
+    pub bytes_checked: usize,
+    pub corrupt: Vec<Address>,
+    pub missing: Vec<Address>,
+}
+
 struct FileLock {
     f: fs::File,
 }
@@ -94,6 +112,31 @@ fn new_random_token() -> String {
     hex::easy_encode_to_string(&gen)
 }
 
+/// Context used when hashing chunk content into its `Address`. Distinct
+/// from `net::FRAME_CONTEXT` even though both happen to read "bupstash",
+/// since libhydrogen contexts are only required to be unique *within*
+/// the construction they key, not across unrelated ones.
+const CONTENT_HASH_CONTEXT: [u8; 8] = *b"bupstash";
+
+/// The construction that produces every `Address` in the repository,
+/// pinned here as the one definition `verify` checks chunks against:
+/// a keyed hash of the exact bytes a storage engine is asked to store
+/// for that address -- the *ciphertext* for an encrypted leaf (never
+/// the plaintext `mount.rs` decrypts it to), the raw plaintext record
+/// list for an interior node (those are never encrypted, see
+/// `htree.rs`). The key is repository-wide rather than per-item,
+/// because content addressing only gives cross-item dedup if identical
+/// content hashes to the same address regardless of which item
+/// uploaded it first -- which in turn requires leaf encryption to be
+/// deterministic per plaintext (the same chunk must always produce the
+/// same ciphertext), or two items with the same plaintext chunk would
+/// collide on one address while disagreeing on what's stored there.
+pub(crate) fn compute_content_address(hash_key: &[u8; hydrogen::HASH_KEYBYTES], data: &[u8]) -> Address {
+    let mut out = [0; hydrogen::HASH_BYTES];
+    hydrogen::hash(data, CONTENT_HASH_CONTEXT, Some(hash_key), &mut out);
+    Address::from_bytes(&out)
+}
+
 impl Repo {
     fn ensure_file_exists(p: &Path) -> Result<(), failure::Error> {
         if p.exists() {
@@ -175,6 +218,10 @@ impl Repo {
             "insert into RepositoryMeta(Key, Value) values('gc-generation', ?);",
             rusqlite::params![new_random_token()],
         )?;
+        tx.execute(
+            "insert into RepositoryMeta(Key, Value) values('content-hash-key', ?);",
+            rusqlite::params![hydrogen::hash_keygen().to_vec()],
+        )?;
         tx.execute(
             "insert into RepositoryMeta(Key, Value) values('storage-engine', ?);",
             rusqlite::params![serde_json::to_string(&engine)?],
@@ -251,11 +298,23 @@ impl Repo {
                 // configurable?
                 Box::new(chunk_storage::LocalStorage::new(&data_dir, 4))
             }
+            StorageEngineSpec::S3 {
+                bucket,
+                prefix,
+                endpoint,
+                region,
+            } => Box::new(chunk_storage::S3Storage::new(
+                &bucket, &prefix, &endpoint, &region,
+            )),
         };
 
         Ok(storage_engine)
     }
 
+    pub(crate) fn path(&self) -> &Path {
+        &self.repo_path
+    }
+
     pub fn gc_generation(&self) -> Result<String, failure::Error> {
         Ok(self.conn.query_row(
             "select value from RepositoryMeta where Key='gc-generation';",
@@ -264,6 +323,24 @@ impl Repo {
         )?)
     }
 
+    /// The repository-wide key used by `compute_content_address`. Every
+    /// chunk, in every item, is addressed under this one key -- never a
+    /// per-item key -- so that two items uploading the same content
+    /// land on the same address and dedup against each other.
+    pub fn content_hash_key(&self) -> Result<[u8; hydrogen::HASH_KEYBYTES], failure::Error> {
+        let bytes: Vec<u8> = self.conn.query_row(
+            "select value from RepositoryMeta where Key='content-hash-key';",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        let mut key = [0; hydrogen::HASH_KEYBYTES];
+        if bytes.len() != key.len() {
+            failure::bail!("content-hash-key has unexpected length");
+        }
+        key.copy_from_slice(&bytes);
+        Ok(key)
+    }
+
     pub fn id(&self) -> Result<String, failure::Error> {
         Ok(self.conn.query_row(
             "select value from RepositoryMeta where Key='id';",
@@ -332,21 +409,28 @@ impl Repo {
         Ok(())
     }
 
+    /// Runs under a shared lock, so a long-running backup never blocks
+    /// GC and vice-versa. Safety has two parts: rotating `gc-generation`
+    /// before computing the reachable set lets a writer detect the race
+    /// at commit time (see `Upload::commit`); the `not_before` grace
+    /// period passed to `storage_engine.gc` below covers the remaining
+    /// window, where a writer starts after rotation but commits while
+    /// this pass is still deleting, so no generation change is ever
+    /// seen. This is the one place that window is closed -- don't
+    /// re-derive the reasoning elsewhere, just reference this comment.
     pub fn gc(&mut self) -> Result<GCStats, failure::Error> {
-        match self.open_mode {
-            OpenMode::Exclusive => (),
-            _ => failure::bail!("unable to collect garbage without an exclusive lock"),
-        }
-
-        let mut reachable: HashSet<Address> = std::collections::HashSet::new();
+        let gc_started_at = std::time::SystemTime::now();
         let mut conn = Repo::open_db(&self.repo_path)?;
         let mut storage_engine = self.storage_engine()?;
+
+        conn.execute(
+            "update RepositoryMeta set value = ? where key = 'gc-generation';",
+            rusqlite::params![new_random_token()],
+        )?;
+
+        let mut reachable: HashSet<Address> = std::collections::HashSet::new();
         let tx = conn.transaction()?;
         {
-            tx.execute(
-                "update RepositoryMeta set value = ? where key = 'gc_generation';",
-                rusqlite::params![new_random_token()],
-            )?;
             let mut stmt = tx.prepare("select Metadata from Items;")?;
             let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
 
@@ -371,11 +455,74 @@ impl Repo {
             }
         }
 
-        // We MUST commit the new gc generation before we start
-        // deleting any chunks.
+        // The new gc-generation was already committed by the update
+        // above, before the reachable set was computed; this only ends
+        // the read transaction before we start deleting any chunks.
         tx.commit()?;
 
-        let stats = storage_engine.gc(&|addr| reachable.contains(&addr))?;
+        let stats = storage_engine.gc(&|addr| reachable.contains(&addr), gc_started_at)?;
+        Ok(stats)
+    }
+
+    pub fn verify(&mut self) -> Result<VerifyStats, failure::Error> {
+        let mut stats = VerifyStats {
+            chunks_checked: 0,
+            bytes_checked: 0,
+            corrupt: Vec::new(),
+            missing: Vec::new(),
+        };
+
+        let hash_key = self.content_hash_key()?;
+        let mut checked: HashSet<Address> = std::collections::HashSet::new();
+        let conn = Repo::open_db(&self.repo_path)?;
+        let mut storage_engine = self.storage_engine()?;
+        let mut stmt = conn.prepare("select Metadata from Items;")?;
+        let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+
+        while let Some(row) = rows.next()? {
+            let metadata: Vec<u8> = row.get(0)?;
+            let metadata: ItemMetadata = bincode::deserialize(&metadata)?;
+            let addr = &metadata.address;
+
+            if checked.contains(addr) {
+                continue;
+            }
+            let mut tr = htree::TreeReader::new(&mut storage_engine, metadata.tree_height, addr);
+            while let Some((height, addr)) = tr.next_addr()? {
+                if checked.contains(&addr) {
+                    continue;
+                }
+                checked.insert(addr);
+
+                let data = match storage_engine.get_chunk(&addr) {
+                    Ok(data) => data,
+                    Err(_) => {
+                        stats.missing.push(addr);
+                        continue;
+                    }
+                };
+
+                // `data` is exactly what the storage engine holds under
+                // `addr` -- the same bytes `compute_content_address` must
+                // have been given when the chunk was uploaded, whether
+                // that's an encrypted leaf or a plaintext interior node.
+                if compute_content_address(&hash_key, &data) != addr {
+                    // Interior nodes are content-addressed too, so a
+                    // mismatch means we can't trust the children it
+                    // claims to have -- don't descend into them.
+                    stats.corrupt.push(addr);
+                    continue;
+                }
+
+                stats.chunks_checked += 1;
+                stats.bytes_checked += data.len();
+
+                if height != 0 {
+                    tr.push_addr(height - 1, &addr)?;
+                }
+            }
+        }
+
         Ok(stats)
     }
 }
@@ -400,4 +547,34 @@ mod tests {
         let v = storage_engine.get_chunk(&addr).unwrap();
         assert_eq!(v, vec![1]);
     }
+
+    // `verify` can't be exercised end to end here without a real
+    // `crypto::VersionedEncryptionHeader` to put in an `ItemMetadata`,
+    // but what it actually depends on for correctness is that
+    // `compute_content_address` is the same keyed construction that
+    // produced the chunk's address in the first place, and that it's
+    // sensitive to the bytes actually stored -- this is a round trip
+    // over exactly that: compute an address, store the chunk under it,
+    // and confirm recomputing the address from the stored bytes matches.
+    #[test]
+    fn content_address_roundtrip_detects_corruption() {
+        let tmp_dir = tempdir::TempDir::new("test_repo").unwrap();
+        let mut path_buf = PathBuf::from(tmp_dir.path());
+        path_buf.push("repo");
+        Repo::init(path_buf.as_path(), StorageEngineSpec::Local).unwrap();
+        let repo = Repo::open(path_buf.as_path(), OpenMode::Shared).unwrap();
+        let hash_key = repo.content_hash_key().unwrap();
+        let mut storage_engine = repo.storage_engine().unwrap();
+
+        let data = vec![1, 2, 3, 4, 5];
+        let addr = compute_content_address(&hash_key, &data);
+        storage_engine.add_chunk(&addr, data).unwrap();
+        storage_engine.sync().unwrap();
+
+        let stored = storage_engine.get_chunk(&addr).unwrap();
+        assert_eq!(compute_content_address(&hash_key, &stored), addr);
+
+        let corrupted = vec![9, 9, 9, 9, 9];
+        assert_ne!(compute_content_address(&hash_key, &corrupted), addr);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,133 @@
+use super::address::Address;
+use super::chunk_storage;
+use super::repository::{ItemMetadata, Repo};
+
+/// How many addresses to accumulate before asking the storage engine
+/// which of them already exist. Bigger batches mean fewer round trips at
+/// the cost of more memory held for buffered chunk bytes.
+const BATCH_SIZE: usize = 512;
+
+/// How many confirmed-present addresses to remember, so a later chunk
+/// that hashes the same (e.g. a run of duplicate blocks) skips the
+/// existence check entirely instead of re-querying the storage engine.
+const KNOWN_PRESENT_CACHE_SIZE: usize = 4 * BATCH_SIZE;
+
+/// Sits between the htree producer and a `chunk_storage::Engine`,
+/// batching existence checks so incremental backups don't re-offer
+/// chunks the storage engine already has. As addresses are emitted they
+/// are buffered; once the buffer is full, any address this session has
+/// already confirmed present is dropped, the rest are checked with a
+/// single `has_chunks` call, and only the misses are actually
+/// transferred.
+pub struct ChunkUploader<'a> {
+    storage_engine: &'a mut dyn chunk_storage::Engine,
+    known_present: lru::LruCache<Address, ()>,
+    pending: Vec<(Address, Vec<u8>)>,
+    used: Vec<Address>,
+}
+
+impl<'a> ChunkUploader<'a> {
+    pub fn new(storage_engine: &'a mut dyn chunk_storage::Engine) -> Self {
+        ChunkUploader {
+            storage_engine,
+            known_present: lru::LruCache::new(KNOWN_PRESENT_CACHE_SIZE),
+            pending: Vec::with_capacity(BATCH_SIZE),
+            used: Vec::new(),
+        }
+    }
+
+    /// Every address this uploader has been asked to store, whether or
+    /// not it turned out to already be present. Used after a GC race to
+    /// know which chunks an in-progress item depends on.
+    pub fn used_addrs(&self) -> &[Address] {
+        &self.used
+    }
+
+    /// Queue `addr`/`data` for upload. May block to flush a batch.
+    pub fn add_chunk(&mut self, addr: Address, data: Vec<u8>) -> Result<(), failure::Error> {
+        self.used.push(addr);
+        if self.known_present.contains(&addr) {
+            return Ok(());
+        }
+        self.pending.push((addr, data));
+        if self.pending.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Check existence for, and transfer, anything still buffered. Must
+    /// be called after the last `add_chunk` to ensure a short final
+    /// batch is not silently dropped.
+    pub fn flush(&mut self) -> Result<(), failure::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.pending, Vec::with_capacity(BATCH_SIZE));
+        let addrs: Vec<Address> = batch.iter().map(|(addr, _)| *addr).collect();
+        let present = self.storage_engine.has_chunks(&addrs)?;
+
+        for ((addr, data), present) in batch.into_iter().zip(present.into_iter()) {
+            if present {
+                self.known_present.put(addr, ());
+                continue;
+            }
+            self.storage_engine.add_chunk(&addr, data)?;
+            self.known_present.put(addr, ());
+        }
+
+        self.storage_engine.sync()
+    }
+}
+
+/// Wraps a `ChunkUploader` with the bookkeeping needed to commit safely
+/// while GC may be running concurrently (see `Repo::gc`'s doc comment
+/// for the generation/grace-period protocol this relies on).
+pub struct Upload<'a> {
+    started_gc_generation: String,
+    uploader: ChunkUploader<'a>,
+}
+
+impl<'a> Upload<'a> {
+    pub fn begin(repo: &Repo, storage_engine: &'a mut dyn chunk_storage::Engine) -> Result<Self, failure::Error> {
+        Ok(Upload {
+            started_gc_generation: repo.gc_generation()?,
+            uploader: ChunkUploader::new(storage_engine),
+        })
+    }
+
+    pub fn add_chunk(&mut self, addr: Address, data: Vec<u8>) -> Result<(), failure::Error> {
+        self.uploader.add_chunk(addr, data)
+    }
+
+    /// Finish uploading and commit `metadata` as a new item. If
+    /// `gc-generation` changed since `begin`, a GC pass may have run
+    /// concurrently, so every chunk this item relies on is re-checked
+    /// with `has_chunks` and anything missing is re-uploaded via
+    /// `reupload` (which must be able to reproduce the chunk's bytes,
+    /// e.g. by re-reading and re-chunking the relevant source data)
+    /// before the item is recorded.
+    pub fn commit(
+        mut self,
+        repo: &mut Repo,
+        metadata: ItemMetadata,
+        reupload: &mut dyn FnMut(&Address) -> Result<Vec<u8>, failure::Error>,
+    ) -> Result<i64, failure::Error> {
+        self.uploader.flush()?;
+
+        if repo.gc_generation()? != self.started_gc_generation {
+            let mut storage_engine = repo.storage_engine()?;
+            let addrs = self.uploader.used_addrs();
+            let present = storage_engine.has_chunks(addrs)?;
+            for (addr, present) in addrs.iter().zip(present.into_iter()) {
+                if !present {
+                    let data = reupload(addr)?;
+                    storage_engine.add_chunk(addr, data)?;
+                }
+            }
+            storage_engine.sync()?;
+        }
+
+        repo.add_item(metadata)
+    }
+}